@@ -1,5 +1,6 @@
 //! Process HTTP connections on the server.
 
+use async_compression::futures::write::{BrotliEncoder, GzipEncoder};
 use async_std::future::{timeout, Future, TimeoutError};
 use async_std::io::{self, BufRead, BufReader};
 use async_std::io::{Read, Write};
@@ -8,62 +9,555 @@ use async_std::task::{Context, Poll};
 use futures_core::ready;
 use http_types::{Method, Request, Response};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use std::pin::Pin;
 
 use crate::{Exception, MAX_HEADERS};
 
+/// How many bytes of body we read from the response at a time when framing
+/// it as chunked transfer-encoding.
+const CHUNK_BUF_SIZE: usize = 8 * 1024;
+
+/// Default cap on the size, in bytes, of a request's header section. See
+/// [`ServerOptions::with_max_head_size`].
+const DEFAULT_MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Resource limits for connections served via [`connect`].
+///
+/// Construct with [`ServerOptions::new`] (or [`Default::default`]) and tune
+/// with the `with_*` builder methods; each listener can hand its own tuned
+/// instance to `connect`.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// How long to wait for a pipelined request before giving up and closing
+    /// the connection.
+    timeout: Duration,
+    /// The maximum number of requests served on a single connection before
+    /// it's forcibly closed.
+    max_requests: usize,
+    /// The maximum number of headers accepted in a single request's head.
+    max_headers: usize,
+    /// The maximum size, in bytes, of a request's header section. Guards
+    /// against a client streaming an endless head into memory.
+    max_head_size: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_requests: 200,
+            max_headers: MAX_HEADERS,
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Create a new instance with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long to wait for a pipelined request before closing the connection.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of requests served on a single connection.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Set the maximum number of headers accepted in a single request's head.
+    pub fn with_max_headers(mut self, max_headers: usize) -> Self {
+        self.max_headers = max_headers;
+        self
+    }
+
+    /// Set the maximum size, in bytes, of a request's header section.
+    pub fn with_max_head_size(mut self, max_head_size: usize) -> Self {
+        self.max_head_size = max_head_size;
+        self
+    }
+}
+
+/// A content-coding negotiated with the client via `Accept-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// The `Content-Encoding` header value for this coding, or `None` for identity.
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Brotli => Some("br"),
+        }
+    }
+}
+
+/// Pick a content-coding to compress the response body with, based on the
+/// request's `Accept-Encoding` header. Prefers `gzip` over `br` since it's
+/// the more universally supported of the two. Honors an explicit `q=0`
+/// ruling a coding out, per RFC 7231 §5.3.1.
+pub(crate) fn negotiate_encoding(accept_encoding: Option<&str>) -> ContentEncoding {
+    let accept_encoding = accept_encoding.unwrap_or("").to_ascii_lowercase();
+    let accepts_coding = |coding: &str| {
+        accept_encoding.split(',').any(|entry| {
+            let mut params = entry.split(';');
+            if params.next().unwrap_or("").trim() != coding {
+                return false;
+            }
+            let rejected = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .any(|q| q.parse::<f32>().unwrap_or(1.0) == 0.0);
+            !rejected
+        })
+    };
+
+    if accepts_coding("gzip") {
+        ContentEncoding::Gzip
+    } else if accepts_coding("br") {
+        ContentEncoding::Brotli
+    } else {
+        ContentEncoding::Identity
+    }
+}
+
+/// A streaming compressor for a response body.
+///
+/// Wraps an `async-compression` encoder writing into an in-memory sink; bytes
+/// written to the encoder only become available in the sink once it's been
+/// flushed.
+#[derive(Debug)]
+enum Compressor {
+    Gzip(GzipEncoder<Vec<u8>>),
+    Brotli(BrotliEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(encoding: ContentEncoding) -> Option<Self> {
+        match encoding {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some(Compressor::Gzip(GzipEncoder::new(Vec::new()))),
+            ContentEncoding::Brotli => Some(Compressor::Brotli(BrotliEncoder::new(Vec::new()))),
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self {
+            Compressor::Gzip(w) => Pin::new(w).poll_write(cx, buf),
+            Compressor::Brotli(w) => Pin::new(w).poll_write(cx, buf),
+        }
+    }
+
+    /// Force any bytes fed so far out into the sink, without ending the stream.
+    ///
+    /// This is the detail that matters for a slow producer: if we only ever
+    /// `finish` the encoder on body EOF, compressed bytes for earlier chunks
+    /// can sit buffered inside the encoder while the client waits on data
+    /// we've technically already "written".
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Compressor::Gzip(w) => Pin::new(w).poll_flush(cx),
+            Compressor::Brotli(w) => Pin::new(w).poll_flush(cx),
+        }
+    }
+
+    /// Finalize the stream, flushing any trailing bytes into the sink.
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Compressor::Gzip(w) => Pin::new(w).poll_close(cx),
+            Compressor::Brotli(w) => Pin::new(w).poll_close(cx),
+        }
+    }
+
+    fn sink_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Compressor::Gzip(w) => w.get_mut(),
+            Compressor::Brotli(w) => w.get_mut(),
+        }
+    }
+}
+
+/// Which step of compressing the next slice of sink bytes we're on.
+#[derive(Debug, PartialEq)]
+enum CompressPhase {
+    /// Reading a chunk of the uncompressed body into `source_buf`.
+    ReadSource,
+    /// Feeding `source_buf[..source_len]` into the compressor.
+    Feed,
+    /// Flushing the compressor so the bytes just fed become available in the sink.
+    Flush,
+    /// Source hit EOF; finalizing the compressor so trailing bytes land in the sink.
+    Close,
+    /// Copying bytes out of the compressor's sink into the caller's buffer.
+    Drain,
+    /// The compressor is fully drained and the source has hit EOF.
+    Done,
+}
+
+/// Wraps a response body, transparently compressing it as it's read.
+#[derive(Debug)]
+struct CompressedBody {
+    source: Response,
+    compressor: Compressor,
+    phase: CompressPhase,
+    source_buf: Vec<u8>,
+    source_len: usize,
+    source_cursor: usize,
+    sink_cursor: usize,
+    source_eof: bool,
+}
+
+impl CompressedBody {
+    fn new(source: Response, compressor: Compressor) -> Self {
+        Self {
+            source,
+            compressor,
+            phase: CompressPhase::ReadSource,
+            source_buf: vec![0; CHUNK_BUF_SIZE],
+            source_len: 0,
+            source_cursor: 0,
+            sink_cursor: 0,
+            source_eof: false,
+        }
+    }
+}
+
+impl Read for CompressedBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.phase {
+                CompressPhase::Done => return Poll::Ready(Ok(0)),
+                CompressPhase::ReadSource => {
+                    let n = ready!(Pin::new(&mut this.source).poll_read(cx, &mut this.source_buf))?;
+                    if n == 0 {
+                        this.source_eof = true;
+                        this.phase = CompressPhase::Close;
+                    } else {
+                        this.source_len = n;
+                        this.source_cursor = 0;
+                        this.phase = CompressPhase::Feed;
+                    }
+                }
+                CompressPhase::Feed if this.source_cursor == this.source_len => {
+                    this.phase = CompressPhase::Flush;
+                }
+                CompressPhase::Feed => {
+                    let n = ready!(this
+                        .compressor
+                        .poll_write(cx, &this.source_buf[this.source_cursor..this.source_len]))?;
+                    this.source_cursor += n;
+                }
+                CompressPhase::Flush => {
+                    ready!(this.compressor.poll_flush(cx))?;
+                    this.sink_cursor = 0;
+                    this.phase = CompressPhase::Drain;
+                }
+                CompressPhase::Close => {
+                    ready!(this.compressor.poll_close(cx))?;
+                    this.sink_cursor = 0;
+                    this.phase = CompressPhase::Drain;
+                }
+                CompressPhase::Drain => {
+                    let sink = this.compressor.sink_mut();
+                    let n = copy_from(sink, &mut this.sink_cursor, buf);
+                    if n > 0 {
+                        return Poll::Ready(Ok(n));
+                    }
+                    sink.clear();
+                    this.sink_cursor = 0;
+                    this.phase = if this.source_eof {
+                        CompressPhase::Done
+                    } else {
+                        CompressPhase::ReadSource
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// The response body source an [`Encoder`] reads from.
+#[derive(Debug)]
+enum Body {
+    Raw(Response),
+    Compressed(CompressedBody),
+}
+
+impl Read for Body {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Body::Raw(r) => Pin::new(r).poll_read(cx, buf),
+            Body::Compressed(c) => Pin::new(c).poll_read(cx, buf),
+        }
+    }
+}
+
+/// What a [`connect`] callback produced for a given request.
+pub enum Upgrade {
+    /// Send this response, then keep serving requests on the connection as usual.
+    Response(Response),
+    /// Send this response, then hand the raw duplex connection back to the
+    /// caller of `connect` instead of continuing the request/response loop.
+    /// Use this for `101 Switching Protocols` (e.g. WebSocket) or an accepted
+    /// `CONNECT` tunnel.
+    Take(Response),
+}
+
+/// How a call to [`connect`] ended.
+pub enum Connection<W> {
+    /// The connection closed normally (EOF, timeout, or `Connection: close`).
+    Close,
+    /// The callback took over the connection via [`Upgrade::Take`]. `reader`
+    /// and `writer` are the still-unconsumed halves of the duplex stream,
+    /// ready for the caller to drive with their own framing.
+    Upgrade {
+        reader: Box<dyn BufRead + Unpin + Send + 'static>,
+        writer: W,
+    },
+}
+
 pub async fn connect<'a, R, W, F, Fut>(
     reader: R,
     mut writer: W,
+    options: ServerOptions,
     callback: F,
-) -> Result<(), Exception>
+) -> Result<Connection<W>, Exception>
 where
     R: Read + Unpin + Send + 'static,
     W: Write + Unpin,
     F: Fn(&mut Request) -> Fut,
-    Fut: Future<Output = Result<Response, Exception>>,
+    Fut: Future<Output = Result<Upgrade, Exception>>,
 {
-    // TODO: make configurable
-    let timeout_duration = Duration::from_secs(10);
-    const MAX_REQUESTS: usize = 200;
-
-    let req = decode(reader).await?;
+    let req = decode(reader, &options).await?;
     let mut num_requests = 0;
-    if let Some((mut req, stream)) = req {
-        let mut stream: Option<Box<dyn BufRead + Unpin + Send + 'static>> = match stream {
-            Some(s) => Some(Box::new(s)),
-            None => None,
-        };
+    if let Some((mut req, mut leftover, mut keep_alive)) = req {
         loop {
             num_requests += 1;
-            if num_requests > MAX_REQUESTS {
-                return Ok(());
+            if num_requests > options.max_requests {
+                return Ok(Connection::Close);
             }
 
+            // A `CONNECT` tunnel has no further HTTP framing once it's
+            // accepted, so never try to keep it alive as a regular
+            // request/response pair.
+            if req.method() == Method::Connect {
+                keep_alive = false;
+            }
+
+            // A client may send `Accept-Encoding` as several header lines
+            // rather than one comma-separated value; fold them into a
+            // single comma-separated string so `negotiate_encoding` sees
+            // every coding the client listed, not just the last line.
+            let accept_encoding = req.header("Accept-Encoding").map(|values| {
+                values
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+            let encoding = negotiate_encoding(accept_encoding.as_deref());
+
             // TODO: what to do when the callback returns Err
-            let mut res = encode(callback(&mut req).await?).await?;
-            let to_decode = match stream {
-                None => req.into_body(),
-                Some(s) => s,
+            let output = callback(&mut req).await?;
+            let to_decode: Box<dyn BufRead + Unpin + Send + 'static> = match leftover {
+                Leftover::Stream(s) => Box::new(s),
+                Leftover::Body => Box::new(BufReader::new(req.into_body())),
+                Leftover::ChunkedBody(cell) => {
+                    // The callback may not have read the body through to
+                    // completion; drain whatever's left of it (and its
+                    // trailer section) so the reader `ChunkedDecoder`
+                    // stashed is actually positioned right after it before
+                    // we resume decoding pipelined requests from it.
+                    io::copy(&mut req.into_body(), &mut io::sink()).await?;
+                    let inner = cell
+                        .lock()
+                        .expect("chunked decoder mutex poisoned")
+                        .take()
+                        .expect("chunked decoder's reader was already taken");
+                    Box::new(inner)
+                }
             };
-            io::copy(&mut res, &mut writer).await?;
-            let (new_request, new_stream) = match timeout(timeout_duration, decode(to_decode)).await
-            {
-                Ok(Ok(Some(r))) => r,
-                Ok(Ok(None)) | Err(TimeoutError { .. }) => break, /* EOF or timeout */
-                Ok(Err(e)) => return Err(e),
+
+            let res = match output {
+                Upgrade::Take(res) => {
+                    let mut res = encode(res, false, ContentEncoding::Identity).await?;
+                    io::copy(&mut res, &mut writer).await?;
+                    return Ok(Connection::Upgrade {
+                        reader: to_decode,
+                        writer,
+                    });
+                }
+                Upgrade::Response(res) => res,
             };
+            let mut res = encode(res, keep_alive, encoding).await?;
+            io::copy(&mut res, &mut writer).await?;
+
+            // The client (or we) asked for the connection to close after this
+            // response; don't wait around for another request.
+            if !keep_alive {
+                return Ok(Connection::Close);
+            }
+
+            let (new_request, new_leftover, new_keep_alive) =
+                match timeout(options.timeout, decode(to_decode, &options)).await {
+                    Ok(Ok(Some(r))) => r,
+                    Ok(Ok(None)) | Err(TimeoutError { .. }) => return Ok(Connection::Close),
+                    Ok(Err(e)) => return Err(e),
+                };
             req = new_request;
-            stream = match new_stream {
-                Some(s) => Some(Box::new(s)),
-                None => None,
-            };
+            keep_alive = new_keep_alive;
+            leftover = new_leftover;
         }
     }
 
-    Ok(())
+    Ok(Connection::Close)
+}
+
+/// Which part of the current chunk we're currently writing out.
+///
+/// A chunk on the wire looks like `<hex-len>\r\n<payload>\r\n`, and the
+/// terminating chunk is the special case `0\r\n\r\n`. We step through these
+/// in order, reading a new chunk of body once the previous chunk's `Suffix`
+/// has been fully flushed.
+#[derive(Debug, PartialEq)]
+enum ChunkPhase {
+    /// Writing the `<hex-len>\r\n` prefix out of `prefix_buf`.
+    Prefix,
+    /// Copying `body_len` bytes of payload out of `body_buf`.
+    Body,
+    /// Writing the trailing `\r\n` after a chunk's payload.
+    Suffix,
+    /// The terminating `0\r\n\r\n` chunk has been fully sent.
+    Done,
+}
+
+/// Per-chunk state used to frame a response body as chunked transfer-encoding.
+#[derive(Debug)]
+struct ChunkedState {
+    phase: ChunkPhase,
+    /// Scratch space the next chunk of body is read into before it's framed.
+    body_buf: Vec<u8>,
+    /// How many bytes of `body_buf` are valid for the chunk in flight.
+    body_len: usize,
+    /// How much of `body_buf[..body_len]` has been copied out so far.
+    body_cursor: usize,
+    /// The `<hex-len>\r\n` (or terminating `0\r\n\r\n`) prefix for the chunk in flight.
+    prefix_buf: Vec<u8>,
+    /// How much of `prefix_buf` has been copied out so far.
+    prefix_cursor: usize,
+    /// How much of the trailing `\r\n` has been copied out so far.
+    suffix_cursor: usize,
+}
+
+impl ChunkedState {
+    fn new() -> Self {
+        Self {
+            // Starts in `Suffix` with the (empty) suffix already exhausted, so the
+            // first poll falls straight through to reading the first body chunk.
+            phase: ChunkPhase::Suffix,
+            body_buf: vec![0; CHUNK_BUF_SIZE],
+            body_len: 0,
+            body_cursor: 0,
+            prefix_buf: Vec::new(),
+            prefix_cursor: 0,
+            suffix_cursor: 2,
+        }
+    }
+}
+
+/// Copy as many bytes as will fit from `src[*cursor..]` into `dst`, advancing `cursor`.
+fn copy_from(src: &[u8], cursor: &mut usize, dst: &mut [u8]) -> usize {
+    let n = std::cmp::min(src.len() - *cursor, dst.len());
+    dst[..n].copy_from_slice(&src[*cursor..*cursor + n]);
+    *cursor += n;
+    n
+}
+
+/// Drive the chunked transfer-encoding state machine, writing as many framed
+/// bytes into `buf` as are ready. Returns `Ok(0)` once the terminating
+/// `0\r\n\r\n` chunk has been fully sent.
+fn poll_chunked_body(
+    state: &mut ChunkedState,
+    body: &mut Body,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    // Every phase below treats `copy_from(..) == 0` as "this phase has
+    // nothing left to copy, move on" - which is also what an empty `buf`
+    // would produce, even when the phase still has bytes staged. Bail out
+    // up front so we never misread "no room to write" as "phase complete"
+    // and skip ahead, discarding whatever's staged in `body_buf`.
+    if buf.is_empty() {
+        return Poll::Ready(Ok(0));
+    }
+    loop {
+        match state.phase {
+            ChunkPhase::Done => return Poll::Ready(Ok(0)),
+            ChunkPhase::Prefix => {
+                let n = copy_from(&state.prefix_buf, &mut state.prefix_cursor, buf);
+                if n > 0 {
+                    return Poll::Ready(Ok(n));
+                }
+                state.phase = if state.body_len == 0 {
+                    ChunkPhase::Done
+                } else {
+                    state.body_cursor = 0;
+                    ChunkPhase::Body
+                };
+            }
+            ChunkPhase::Body => {
+                let n = copy_from(
+                    &state.body_buf[..state.body_len],
+                    &mut state.body_cursor,
+                    buf,
+                );
+                if n > 0 {
+                    return Poll::Ready(Ok(n));
+                }
+                state.suffix_cursor = 0;
+                state.phase = ChunkPhase::Suffix;
+            }
+            ChunkPhase::Suffix => {
+                let n = copy_from(b"\r\n", &mut state.suffix_cursor, buf);
+                if n > 0 {
+                    return Poll::Ready(Ok(n));
+                }
+
+                // The chunk in flight is fully flushed; read the next one from the body.
+                let n = ready!(Pin::new(&mut *body).poll_read(cx, &mut state.body_buf))?;
+                if n == 0 {
+                    state.prefix_buf = b"0\r\n\r\n".to_vec();
+                    state.body_len = 0;
+                } else {
+                    state.prefix_buf = format!("{:x}\r\n", n).into_bytes();
+                    state.body_len = n;
+                }
+                state.prefix_cursor = 0;
+                state.phase = ChunkPhase::Prefix;
+            }
+        }
+    }
 }
 
 /// A streaming HTTP encoder.
@@ -77,54 +571,106 @@ pub struct Encoder {
     headers: Vec<u8>,
     /// Check whether we're done sending headers.
     headers_done: bool,
-    /// Response containing the HTTP body to be sent.
-    response: Response,
+    /// The HTTP body to be sent, possibly wrapped in a compressor.
+    body: Body,
     /// Check whether we're done with the body.
     body_done: bool,
     /// Keep track of how many bytes have been read from the body stream.
     body_bytes_read: usize,
+    /// `Some` when the body is being framed as `Transfer-Encoding: chunked`,
+    /// because the response didn't have a known length up front, or because
+    /// it's being compressed and so its encoded length can't be known ahead
+    /// of time either way.
+    chunked: Option<ChunkedState>,
 }
 
 impl Encoder {
     /// Create a new instance.
-    pub(crate) fn new(headers: Vec<u8>, response: Response) -> Self {
+    ///
+    /// `is_bodiless` marks responses (e.g. `101 Switching Protocols`) whose
+    /// status forbids a body outright, regardless of what the `Response` has
+    /// set: no body bytes are read from it at all.
+    pub(crate) fn new(
+        headers: Vec<u8>,
+        response: Response,
+        encoding: ContentEncoding,
+        is_bodiless: bool,
+    ) -> Self {
+        let body = match Compressor::new(encoding) {
+            Some(compressor) => Body::Compressed(CompressedBody::new(response, compressor)),
+            None => Body::Raw(response),
+        };
+        let chunked = if is_bodiless {
+            None
+        } else {
+            match &body {
+                Body::Compressed(_) => Some(ChunkedState::new()),
+                Body::Raw(r) if r.len().is_none() => Some(ChunkedState::new()),
+                Body::Raw(_) => None,
+            }
+        };
         Self {
-            response,
+            body,
             headers,
             cursor: 0,
             headers_done: false,
-            body_done: false,
+            body_done: is_bodiless,
             body_bytes_read: 0,
+            chunked,
         }
     }
 }
 
 impl Read for Encoder {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
         // Send the headers. As long as the headers aren't fully sent yet we
         // keep sending more of the headers.
         let mut bytes_read = 0;
-        if !self.headers_done {
-            let len = std::cmp::min(self.headers.len() - self.cursor, buf.len());
-            let range = self.cursor..self.cursor + len;
-            buf[0..len].copy_from_slice(&mut self.headers[range]);
-            self.cursor += len;
-            if self.cursor == self.headers.len() {
-                self.headers_done = true;
+        if !this.headers_done {
+            let len = std::cmp::min(this.headers.len() - this.cursor, buf.len());
+            let range = this.cursor..this.cursor + len;
+            buf[0..len].copy_from_slice(&mut this.headers[range]);
+            this.cursor += len;
+            if this.cursor == this.headers.len() {
+                this.headers_done = true;
             }
             bytes_read += len;
+
+            // We just wrote header bytes into `buf`; don't also poll the
+            // body this round. `ready!` below returns `Pending` straight
+            // through on a slow body, and the caller (e.g. `io::copy`)
+            // ignores `buf` when we return `Pending`, which would silently
+            // drop the header bytes we just wrote. Report what we have and
+            // let the next poll pick the body up from here.
+            if bytes_read > 0 {
+                return Poll::Ready(Ok(bytes_read));
+            }
         }
 
-        if !self.body_done {
-            let n = ready!(Pin::new(&mut self.response).poll_read(cx, &mut buf[bytes_read..]))?;
+        // An empty `buf` can't take any body bytes; `Ok(0)` from the body
+        // here would only mean "no room", not EOF, so don't mark it done.
+        if !this.body_done && bytes_read < buf.len() {
+            let n = if let Some(state) = this.chunked.as_mut() {
+                ready!(poll_chunked_body(
+                    state,
+                    &mut this.body,
+                    cx,
+                    &mut buf[bytes_read..]
+                ))?
+            } else {
+                let n = ready!(Pin::new(&mut this.body).poll_read(cx, &mut buf[bytes_read..]))?;
+                this.body_bytes_read += n;
+                n
+            };
             bytes_read += n;
-            self.body_bytes_read += n;
-            if bytes_read == 0 {
-                self.body_done = true;
+            if n == 0 {
+                this.body_done = true;
             }
         }
 
@@ -133,46 +679,340 @@ impl Read for Encoder {
 }
 
 /// Encode an HTTP request on the server.
+///
+/// `keep_alive` reflects the decision made in [`decode`]/[`connect`] about
+/// whether the connection will stay open after this response, and is
+/// reflected back to the client via the `Connection` header. `encoding`
+/// transparently compresses the body per the request's `Accept-Encoding`,
+/// as negotiated by [`negotiate_encoding`].
 // TODO: return a reader in the response
-pub async fn encode(res: Response) -> io::Result<Encoder> {
+pub async fn encode(
+    res: Response,
+    keep_alive: bool,
+    encoding: ContentEncoding,
+) -> io::Result<Encoder> {
+    // If the response already carries an encoded body (e.g. a pre-compressed
+    // static asset the callback serves as-is), never compress it again:
+    // compressing already-compressed bytes would corrupt the body, and
+    // emitting our own coding alongside the existing header would produce
+    // two conflicting `Content-Encoding` headers.
+    let encoding = if res.header("Content-Encoding").is_some() {
+        ContentEncoding::Identity
+    } else {
+        encoding
+    };
+
     let mut buf: Vec<u8> = vec![];
 
     let reason = res.status().canonical_reason();
     let status = res.status();
     std::io::Write::write_fmt(&mut buf, format_args!("HTTP/1.1 {} {}\r\n", status, reason))?;
 
-    // If the body isn't streaming, we can set the content-length ahead of time. Else we need to
-    // send all items in chunks.
-    if let Some(len) = res.len() {
-        std::io::Write::write_fmt(&mut buf, format_args!("Content-Length: {}\r\n", len))?;
+    // `101 Switching Protocols` (and other bodiless statuses) can't carry a
+    // body at all: whatever comes after it is the raw upgraded-protocol
+    // stream, not an HTTP entity, so skip body framing headers entirely.
+    let is_bodiless = status.is_informational()
+        || status == http_types::StatusCode::NoContent
+        || status == http_types::StatusCode::NotModified;
+
+    if is_bodiless {
+        // No `Content-Length`/`Transfer-Encoding`/`Content-Encoding` for a
+        // response with no body.
     } else {
-        std::io::Write::write_fmt(&mut buf, format_args!("Transfer-Encoding: chunked\r\n"))?;
-        panic!("chunked encoding is not implemented yet");
-        // See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
-        //      https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Trailer
+        if encoding == ContentEncoding::Identity && res.len().is_some() {
+            std::io::Write::write_fmt(
+                &mut buf,
+                format_args!("Content-Length: {}\r\n", res.len().unwrap()),
+            )?;
+        } else {
+            std::io::Write::write_fmt(&mut buf, format_args!("Transfer-Encoding: chunked\r\n"))?;
+            // TODO: serialize any trailer headers set on `res` after the final
+            // zero-size chunk once `http_types::Response` exposes a sync way to
+            // read them back out.
+            // See: https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Transfer-Encoding
+            //      https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Trailer
+        }
+
+        if let Some(coding) = encoding.header_value() {
+            std::io::Write::write_fmt(&mut buf, format_args!("Content-Encoding: {}\r\n", coding))?;
+        }
     }
 
+    std::io::Write::write_fmt(
+        &mut buf,
+        format_args!(
+            "Connection: {}\r\n",
+            if status.is_informational() {
+                "Upgrade"
+            } else if keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            }
+        ),
+    )?;
+
+    // `true` only when we wrote our own `Content-Encoding` header above;
+    // skip echoing `res`'s original value in that case to avoid a duplicate.
+    let wrote_content_encoding = !is_bodiless && encoding.header_value().is_some();
+
     for (header, value) in res.headers().iter() {
-        std::io::Write::write_fmt(&mut buf, format_args!("{}: {}\r\n", header.as_str(), value))?
+        let name = header.as_str();
+        // We already serialized these ourselves above, based on `res`'s
+        // status/length/encoding; re-emitting whatever the callback also
+        // set for them would send duplicate (and potentially conflicting)
+        // headers for the same thing.
+        if name.eq_ignore_ascii_case("Connection")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
+            || name.eq_ignore_ascii_case("Content-Length")
+            || (wrote_content_encoding && name.eq_ignore_ascii_case("Content-Encoding"))
+        {
+            continue;
+        }
+        std::io::Write::write_fmt(&mut buf, format_args!("{}: {}\r\n", name, value))?
     }
 
     std::io::Write::write_fmt(&mut buf, format_args!("\r\n"))?;
-    Ok(Encoder::new(buf, res))
+    Ok(Encoder::new(buf, res, encoding, is_bodiless))
+}
+
+/// Maximum chunk size accepted when decoding a chunked request body.
+///
+/// Guards against a malformed or malicious peer claiming an enormous chunk
+/// size and causing us to read an unbounded amount into memory.
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8mb
+
+/// Which part of the chunked framing we're currently consuming from the wire.
+#[derive(Debug)]
+enum DecodeState {
+    /// Reading the `<hex-len>[;ext]\r\n` size line.
+    ChunkSize,
+    /// Reading exactly `remaining` bytes of chunk payload.
+    ChunkBody { remaining: usize },
+    /// Consuming the `\r\n` that terminates a chunk's payload.
+    ChunkEnd,
+    /// Consuming trailer header lines up to the blank line that ends them.
+    Trailers,
+    /// The terminating chunk and any trailers have been fully consumed.
+    Done,
+}
+
+/// Decodes an HTTP `Transfer-Encoding: chunked` request body.
+///
+/// Wraps a [`BufReader`] and strips the chunk framing, yielding only the
+/// decoded payload bytes to its caller. The inner reader is kept behind a
+/// shared cell (see [`ChunkedDecoder::inner_handle`]) rather than owned
+/// outright, so once this decoder reaches [`DecodeState::Done`] the reader
+/// it leaves behind - positioned right after the trailer section - can be
+/// recovered by [`decode`]/[`connect`] to read a subsequent pipelined
+/// request off the same connection.
+#[derive(Debug)]
+pub(crate) struct ChunkedDecoder<R> {
+    inner: Arc<Mutex<Option<BufReader<R>>>>,
+    state: DecodeState,
+    /// Scratch buffer used to accumulate a chunk-size or trailer line.
+    line_buf: Vec<u8>,
+}
+
+impl<R: Read + Unpin> ChunkedDecoder<R> {
+    pub(crate) fn new(inner: BufReader<R>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Some(inner))),
+            state: DecodeState::ChunkSize,
+            line_buf: Vec::new(),
+        }
+    }
+
+    /// A handle to the inner reader. The cell holds the reader from the start
+    /// and the decoder reads through it on every poll; only once this decoder
+    /// reaches [`DecodeState::Done`] is the reader positioned right after the
+    /// body and ready to be taken.
+    pub(crate) fn inner_handle(&self) -> Arc<Mutex<Option<BufReader<R>>>> {
+        self.inner.clone()
+    }
+}
+
+/// Read from `reader` until a `\n` is found, appending everything read
+/// (including the `\n`) to `line_buf`.
+fn poll_fill_line<R: BufRead + Unpin>(
+    mut reader: Pin<&mut R>,
+    cx: &mut Context<'_>,
+    line_buf: &mut Vec<u8>,
+) -> Poll<io::Result<()>> {
+    loop {
+        let available = ready!(reader.as_mut().poll_fill_buf(cx))?;
+        if available.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected EOF while reading chunk framing",
+            )));
+        }
+        match available.iter().position(|&b| b == b'\n') {
+            Some(idx) => {
+                line_buf.extend_from_slice(&available[..=idx]);
+                let used = idx + 1;
+                reader.as_mut().consume(used);
+                return Poll::Ready(Ok(()));
+            }
+            None => {
+                let used = available.len();
+                line_buf.extend_from_slice(available);
+                reader.as_mut().consume(used);
+            }
+        }
+    }
+}
+
+/// Parse a `<hex-len>[;ext]\r\n` chunk-size line, ignoring any chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> io::Result<usize> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size"))?;
+    let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+    let size_str = line.split(';').next().unwrap_or("").trim();
+    let size = usize::from_str_radix(size_str, 16)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size"))?;
+    if size > MAX_CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk size exceeds maximum allowed",
+        ));
+    }
+    Ok(size)
+}
+
+impl<R: Read + Unpin> Read for ChunkedDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut guard = this.inner.lock().expect("chunked decoder mutex poisoned");
+        let inner = guard
+            .as_mut()
+            .expect("chunked decoder polled after its reader was taken");
+        loop {
+            match this.state {
+                DecodeState::Done => return Poll::Ready(Ok(0)),
+                DecodeState::ChunkSize => {
+                    this.line_buf.clear();
+                    ready!(poll_fill_line(Pin::new(inner), cx, &mut this.line_buf))?;
+                    let size = parse_chunk_size(&this.line_buf)?;
+                    this.state = if size == 0 {
+                        DecodeState::Trailers
+                    } else {
+                        DecodeState::ChunkBody { remaining: size }
+                    };
+                }
+                DecodeState::ChunkBody { remaining: 0 } => {
+                    this.state = DecodeState::ChunkEnd;
+                }
+                DecodeState::ChunkBody { remaining } => {
+                    let to_read = std::cmp::min(remaining, buf.len());
+                    if to_read == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let n = ready!(Pin::new(&mut *inner).poll_read(cx, &mut buf[..to_read]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "unexpected EOF while reading chunk payload",
+                        )));
+                    }
+                    this.state = DecodeState::ChunkBody {
+                        remaining: remaining - n,
+                    };
+                    return Poll::Ready(Ok(n));
+                }
+                DecodeState::ChunkEnd => {
+                    this.line_buf.clear();
+                    ready!(poll_fill_line(Pin::new(inner), cx, &mut this.line_buf))?;
+                    this.state = DecodeState::ChunkSize;
+                }
+                DecodeState::Trailers => {
+                    this.line_buf.clear();
+                    ready!(poll_fill_line(Pin::new(inner), cx, &mut this.line_buf))?;
+                    if this.line_buf == b"\r\n" || this.line_buf == b"\n" {
+                        this.state = DecodeState::Done;
+                    }
+                    // else: another trailer header line, keep consuming.
+                }
+            }
+        }
+    }
+}
+
+/// Determine whether a connection should be kept alive after this request,
+/// following standard `Connection` header semantics for the request's HTTP
+/// version: HTTP/1.1 defaults to keep-alive unless told to `close` (or
+/// `upgrade`); HTTP/1.0 defaults to closing unless told `keep-alive`.
+fn keep_alive(version: u8, connection: Option<&str>) -> bool {
+    let has_token = |token: &str| {
+        connection
+            .unwrap_or("")
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+    if has_token("close") || has_token("upgrade") {
+        false
+    } else if version == 0 {
+        has_token("keep-alive")
+    } else {
+        true
+    }
+}
+
+/// How to resume decoding pipelined requests off the same connection, once
+/// the current one's body has been dealt with.
+pub(crate) enum Leftover<R> {
+    /// The request had no body; this reader is immediately ready to decode
+    /// the next request's head from.
+    Stream(BufReader<R>),
+    /// The request's body (e.g. `Content-Length`) reads directly off the
+    /// connection with no extra framing to strip, so once it's drained,
+    /// `req.into_body()` itself is the reader to resume from.
+    Body,
+    /// The request's body is `Transfer-Encoding: chunked`. The cell is shared
+    /// with the [`ChunkedDecoder`] and always holds the reader; once the
+    /// decoder has consumed the body through its trailer section, that reader
+    /// is positioned right after it.
+    ChunkedBody(Arc<Mutex<Option<BufReader<R>>>>),
 }
 
 /// Decode an HTTP request on the server.
-pub async fn decode<R>(reader: R) -> Result<Option<(Request, Option<BufReader<R>>)>, Exception>
+///
+/// Returns the parsed request, a [`Leftover`] describing how to resume
+/// decoding a subsequent pipelined request off the same connection, and
+/// whether the connection should be kept alive for another request.
+pub async fn decode<R>(
+    reader: R,
+    options: &ServerOptions,
+) -> Result<Option<(Request, Leftover<R>, bool)>, Exception>
 where
     R: Read + Unpin + Send + 'static,
 {
     let mut reader = BufReader::new(reader);
     let mut buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut headers = vec![httparse::EMPTY_HEADER; options.max_headers];
     let mut httparse_req = httparse::Request::new(&mut headers);
 
     // Keep reading bytes from the stream until we hit the end of the stream.
     loop {
-        let bytes_read = reader.read_until(b'\n', &mut buf).await?;
+        if buf.len() >= options.max_head_size {
+            return Err("Head byte length too large".into());
+        }
+
+        // Cap how many bytes this single `read_until` call may append to
+        // `buf` at our remaining budget. Without this, a peer that streams
+        // one endless line with no `\n` would make `read_until` itself
+        // buffer without limit - the length check at the top of this loop
+        // only runs once a full line lands, which never happens for such a
+        // line.
+        let remaining = (options.max_head_size - buf.len()) as u64;
+        let bytes_read = (&mut reader)
+            .take(remaining)
+            .read_until(b'\n', &mut buf)
+            .await?;
         // No more bytes are yielded from the stream.
         if bytes_read == 0 {
             return Ok(None);
@@ -197,7 +1037,7 @@ where
     let uri = httparse_req.path.ok_or_else(|| "No uri found")?;
     let uri = url::Url::parse(uri)?;
     let version = httparse_req.version.ok_or_else(|| "No version found")?;
-    if version != 1 {
+    if version > 1 {
         return Err("Unsupported HTTP version".into());
     }
     let mut req = Request::new(Method::from_str(method)?, uri);
@@ -205,8 +1045,29 @@ where
         req = req.set_header(header.name, std::str::from_utf8(header.value)?)?;
     }
 
-    // Process the body if `Content-Length` was passed.
-    if let Some(content_length) = httparse_req
+    let connection_header = httparse_req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Connection"))
+        .and_then(|h| std::str::from_utf8(h.value).ok());
+    let keep_alive = keep_alive(version, connection_header);
+
+    // `Transfer-Encoding: chunked` takes priority over `Content-Length` per spec.
+    let is_chunked = httparse_req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map_or(false, |v| v.to_ascii_lowercase().contains("chunked"));
+
+    if is_chunked {
+        let decoder = ChunkedDecoder::new(reader);
+        let leftover = Leftover::ChunkedBody(decoder.inner_handle());
+        req = req.set_body(decoder);
+
+        // Return the request.
+        Ok(Some((req, leftover, keep_alive)))
+    } else if let Some(content_length) = httparse_req
         .headers
         .iter()
         .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
@@ -220,11 +1081,158 @@ where
             req = req.set_len(len);
 
             // Return the request.
-            Ok(Some((req, None)))
+            Ok(Some((req, Leftover::Body, keep_alive)))
         } else {
             return Err("Invalid value for Content-Length".into());
         }
     } else {
-        Ok(Some((req, Some(reader))))
+        Ok(Some((req, Leftover::Stream(reader), keep_alive)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    /// A `Read` that reports `Poll::Pending` exactly once before yielding
+    /// `inner`'s bytes, to exercise the case where a streamed body isn't
+    /// immediately ready on the very first poll.
+    struct PendingOnceThenRead<R> {
+        inner: R,
+        pending_returned: bool,
+    }
+
+    impl<R> PendingOnceThenRead<R> {
+        fn new(inner: R) -> Self {
+            Self {
+                inner,
+                pending_returned: false,
+            }
+        }
+    }
+
+    impl<R: Read + Unpin> Read for PendingOnceThenRead<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if !self.pending_returned {
+                self.pending_returned = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    fn find_head_end(wire: &[u8]) -> usize {
+        wire.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("no head terminator")
+            + 4
+    }
+
+    /// Reassemble a chunked-transfer-encoded payload back into its raw bytes,
+    /// as a reference independent of `ChunkedDecoder`/`poll_chunked_body`.
+    fn unchunk(mut wire: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let nl = wire.iter().position(|&b| b == b'\n').unwrap();
+            let size_line = std::str::from_utf8(&wire[..nl])
+                .unwrap()
+                .trim_end_matches('\r');
+            let size = usize::from_str_radix(size_line, 16).unwrap();
+            wire = &wire[nl + 1..];
+            if size == 0 {
+                break;
+            }
+            out.extend_from_slice(&wire[..size]);
+            wire = &wire[size + 2..]; // payload + trailing \r\n
+        }
+        out
+    }
+
+    async fn encode_to_vec(res: Response, encoding: ContentEncoding) -> Vec<u8> {
+        let mut encoder = encode(res, true, encoding).await.unwrap();
+        let mut out = Vec::new();
+        encoder.read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    #[async_std::test]
+    async fn chunked_encode_multi_chunk_body_round_trips() {
+        let body = vec![b'a'; CHUNK_BUF_SIZE * 2 + 10];
+        let mut res = Response::new(http_types::StatusCode::Ok);
+        res.set_body(Cursor::new(body.clone()));
+
+        let wire = encode_to_vec(res, ContentEncoding::Identity).await;
+        let head_end = find_head_end(&wire);
+        let head = std::str::from_utf8(&wire[..head_end]).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert_eq!(unchunk(&wire[head_end..]), body);
+    }
+
+    #[async_std::test]
+    async fn chunked_encode_zero_length_body_sends_terminator_only() {
+        let mut res = Response::new(http_types::StatusCode::Ok);
+        res.set_body(Cursor::new(Vec::<u8>::new()));
+
+        let wire = encode_to_vec(res, ContentEncoding::Identity).await;
+        let head_end = find_head_end(&wire);
+        assert_eq!(&wire[head_end..], b"0\r\n\r\n");
+    }
+
+    #[async_std::test]
+    async fn headers_are_not_lost_when_body_is_pending_on_first_poll() {
+        let mut res = Response::new(http_types::StatusCode::Ok);
+        res.set_body(PendingOnceThenRead::new(Cursor::new(b"hello".to_vec())));
+
+        let mut encoder = encode(res, true, ContentEncoding::Identity).await.unwrap();
+        let mut wire = Vec::new();
+        encoder.read_to_end(&mut wire).await.unwrap();
+
+        let head_end = find_head_end(&wire);
+        let head = std::str::from_utf8(&wire[..head_end]).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(unchunk(&wire[head_end..]), b"hello");
+    }
+
+    #[async_std::test]
+    async fn chunked_decoder_reassembles_multi_chunk_payload() {
+        let wire = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(BufReader::new(Cursor::new(wire.to_vec())));
+
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"Wikipedia");
+    }
+
+    #[async_std::test]
+    async fn chunked_decoder_zero_length_body_yields_nothing() {
+        let wire = b"0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(BufReader::new(Cursor::new(wire.to_vec())));
+
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).await.unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[async_std::test]
+    async fn chunked_decoder_leaves_inner_reader_positioned_after_trailers() {
+        let wire = b"2\r\nhi\r\n0\r\n\r\nnext request bytes";
+        let decoder = ChunkedDecoder::new(BufReader::new(Cursor::new(wire.to_vec())));
+        let handle = decoder.inner_handle();
+        let mut decoder = decoder;
+
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"hi");
+
+        let mut inner = handle.lock().unwrap().take().expect("inner reader stashed");
+        let mut rest = Vec::new();
+        inner.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"next request bytes");
     }
 }